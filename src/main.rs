@@ -3,21 +3,30 @@ extern crate specs_derive;
 
 use ggez::{
     conf,
-    event::{self, MouseButton},
-    graphics::{self, Color, Point2, Rect},
-    Context, GameResult,
+    event::{self, Keycode, Mod, MouseButton},
+    graphics::{self, Color, Font, Point2, Rect, Text},
+    timer, Context, GameResult,
 };
 use ncollide2d::{
     bounding_volume::{aabb, HasBoundingVolume, AABB},
     math::{Isometry, Vector},
-    partitioning::{DBVTLeaf, DBVT, BoundingVolumeInterferencesCollector},
-    shape::{Ball, Cuboid},
+    partitioning::{DBVTLeaf, DBVTLeafId, DBVT, BoundingVolumeInterferencesCollector},
+    query,
+    shape::{Ball, Cuboid, Shape},
 };
+use rand::{Rng, SeedableRng, XorShiftRng};
 use specs::prelude::*;
+use specs::storage::ComponentEvent;
+use specs::ReaderId;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::f32::consts::PI;
 
 type Float = f32;
 type Vec = Vector<Float>;
 
+const DT: Float = 0.05;
+const MAX_FRAME_TIME: Float = 0.25;
+
 const WHITE: Color = graphics::Color {
     r: 1.0,
     g: 1.0,
@@ -31,14 +40,55 @@ const RED: Color = graphics::Color {
     a: 1.0,
 };
 
-struct Bvh(DBVT<Float, (), AABB<Float>>);
+struct Bvh(DBVT<Float, Entity, AABB<Float>>);
 impl Bvh {
     fn new() -> Bvh {
         Bvh(DBVT::new())
     }
 }
 
-#[derive(Component, Debug)]
+#[derive(Clone)]
+struct Prng(XorShiftRng);
+impl Prng {
+    fn new() -> Prng {
+        Prng(XorShiftRng::from_seed([1, 2, 3, 4]))
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum Broadphase {
+    Dbvt,
+    SpatialHash,
+}
+impl Default for Broadphase {
+    fn default() -> Broadphase {
+        Broadphase::Dbvt
+    }
+}
+
+#[derive(Default)]
+struct Candidates(Vec<(Entity, Entity)>);
+
+const CELL_SIZE: Float = 32.0;
+
+fn spread_bits(v: u32) -> u64 {
+    let mut x = v as u64;
+    x = (x | (x << 16)) & 0x0000_FFFF_0000_FFFF;
+    x = (x | (x << 8)) & 0x00FF_00FF_00FF_00FF;
+    x = (x | (x << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+    x = (x | (x << 1)) & 0x5555_5555_5555_5555;
+    x
+}
+
+fn morton_key(cx: i32, cy: i32) -> u64 {
+    const BIAS: i64 = 1 << 20;
+    let ux = (cx as i64 + BIAS) as u32;
+    let uy = (cy as i64 + BIAS) as u32;
+    spread_bits(ux) | (spread_bits(uy) << 1)
+}
+
+#[derive(Component, Debug, Clone)]
 struct Collider {
     colliding: bool,
 }
@@ -51,20 +101,44 @@ impl Collider {
 #[derive(Component, Debug)]
 struct Position {
     vec: Vec,
+    prev: Vec,
+    dirty: bool,
 }
 impl Position {
     fn new(vec: Vec) -> Position {
-        Position { vec }
+        Position {
+            vec,
+            prev: vec,
+            dirty: true,
+        }
     }
 }
 
-#[derive(Component, Debug)]
+#[derive(Component, Debug, Default)]
+#[storage(FlaggedStorage<Self, NullStorage<Self>>)]
+struct BvhLeaf;
+
+#[derive(Component, Debug, Clone, Copy)]
 enum Geometry {
     Circle(f32),
     Square(f32),
 }
 
-#[derive(Component, Debug)]
+fn bounding_shape(geometry: &Geometry) -> Box<HasBoundingVolume<Float, AABB<Float>>> {
+    match geometry {
+        Geometry::Circle(radius) => Box::new(Ball::new(*radius)),
+        Geometry::Square(length) => Box::new(Cuboid::new(Vec::new(length / 2.0, length / 2.0))),
+    }
+}
+
+fn narrow_shape(geometry: &Geometry) -> Box<Shape<Float>> {
+    match geometry {
+        Geometry::Circle(radius) => Box::new(Ball::new(*radius)),
+        Geometry::Square(length) => Box::new(Cuboid::new(Vec::new(length / 2.0, length / 2.0))),
+    }
+}
+
+#[derive(Component, Debug, Clone)]
 struct Velocity {
     vec: Vec,
 }
@@ -74,63 +148,471 @@ impl Velocity {
     }
 }
 
+#[derive(Component, Debug, Clone)]
+struct Emitter {
+    rate: Float,
+    spread: Float,
+    speed: Float,
+    lifetime: Float,
+    cooldown: Float,
+}
+impl Emitter {
+    fn new(rate: Float, spread: Float, speed: Float, lifetime: Float) -> Emitter {
+        Emitter {
+            rate,
+            spread,
+            speed,
+            lifetime,
+            cooldown: 0.0,
+        }
+    }
+}
+
+#[derive(Component, Debug, Clone)]
+struct Lifetime {
+    remaining: Float,
+}
+impl Lifetime {
+    fn new(seconds: Float) -> Lifetime {
+        Lifetime { remaining: seconds }
+    }
+}
+
+#[derive(Component, Debug, Default)]
+#[storage(NullStorage)]
+struct KillOnCollision;
+
+#[derive(Component, Debug, Clone, Copy)]
+struct NetId(u32);
+
+#[derive(Component, Debug, Default)]
+#[storage(NullStorage)]
+struct Owned;
+
+#[derive(Component, Debug, Default)]
+#[storage(NullStorage)]
+struct Remote;
+
+struct Packet {
+    net_id: NetId,
+    x: Float,
+    y: Float,
+}
+
+struct Connection {
+    outbound: Vec<Packet>,
+    inbound: VecDeque<Packet>,
+}
+impl Connection {
+    fn new() -> Connection {
+        Connection {
+            outbound: vec![],
+            inbound: VecDeque::new(),
+        }
+    }
+}
+
 struct VelocitySys;
 impl<'a> System<'a> for VelocitySys {
     type SystemData = (ReadStorage<'a, Velocity>, WriteStorage<'a, Position>);
     fn run(&mut self, (vel, mut pos): Self::SystemData) {
         for (vel, pos) in (&vel, &mut pos).join() {
-            pos.vec.x += vel.vec.x * 0.05;
-            pos.vec.y += vel.vec.y * 0.05;
+            pos.prev = pos.vec;
+            pos.vec.x += vel.vec.x * DT;
+            pos.vec.y += vel.vec.y * DT;
+            pos.dirty = true;
         }
     }
 }
 
-struct BvhSys;
-impl<'a> System<'a> for BvhSys {
+struct EmitterSys;
+impl<'a> System<'a> for EmitterSys {
     type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, Emitter>,
+        WriteStorage<'a, Position>,
+        WriteStorage<'a, Velocity>,
+        WriteStorage<'a, Geometry>,
+        WriteStorage<'a, Collider>,
+        WriteStorage<'a, Lifetime>,
+        WriteStorage<'a, KillOnCollision>,
+        WriteExpect<'a, Prng>,
+    );
+    fn run(
+        &mut self,
+        (entities, mut emitter, mut pos, mut vel, mut geometry, mut collider, mut lifetime, mut kill_on_collision, mut prng): Self::SystemData,
+    ) {
+        let mut spawns = vec![];
+        for (emitter, pos) in (&mut emitter, &pos).join() {
+            emitter.cooldown -= DT;
+            if emitter.cooldown <= 0.0 {
+                emitter.cooldown += 1.0 / emitter.rate;
+                let angle = (prng.0.gen::<f32>() - 0.5) * emitter.spread;
+                let dir = Vec::new(angle.cos(), angle.sin());
+                spawns.push((pos.vec, dir * emitter.speed, emitter.lifetime));
+            }
+        }
+        for (spawn_pos, spawn_vel, spawn_lifetime) in spawns {
+            let shape = if prng.0.gen::<f32>() < 0.5 {
+                Geometry::Circle(prng.0.gen::<f32>() * 20.0 + 10.0)
+            } else {
+                Geometry::Square(prng.0.gen::<f32>() * 40.0 + 20.0)
+            };
+            let entity = entities.create();
+            pos.insert(entity, Position::new(spawn_pos)).unwrap();
+            vel.insert(entity, Velocity::new(spawn_vel)).unwrap();
+            geometry.insert(entity, shape).unwrap();
+            collider.insert(entity, Collider::new()).unwrap();
+            lifetime.insert(entity, Lifetime::new(spawn_lifetime)).unwrap();
+            kill_on_collision.insert(entity, KillOnCollision).unwrap();
+        }
+    }
+}
+
+struct LifetimeSys;
+impl<'a> System<'a> for LifetimeSys {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, Lifetime>,
+        ReadStorage<'a, Collider>,
+        ReadStorage<'a, KillOnCollision>,
+    );
+    fn run(&mut self, (entities, mut lifetime, collider, kill_on_collision): Self::SystemData) {
+        for (entity, lifetime) in (&entities, &mut lifetime).join() {
+            lifetime.remaining -= DT;
+            if lifetime.remaining <= 0.0 {
+                entities.delete(entity).unwrap();
+            }
+        }
+        for (entity, collider, _) in (&entities, &collider, &kill_on_collision).join() {
+            if collider.colliding {
+                entities.delete(entity).unwrap();
+            }
+        }
+    }
+}
+
+// Offset applied when looping a packet back as if it arrived from a remote
+// peer, so it lands on a NetId distinct from the Owned entity that sent it.
+const REMOTE_ID_OFFSET: u32 = 1_000_000;
+
+struct TransmitSys;
+impl<'a> System<'a> for TransmitSys {
+    type SystemData = (
+        ReadStorage<'a, Owned>,
         ReadStorage<'a, Position>,
+        ReadStorage<'a, NetId>,
+        WriteExpect<'a, Connection>,
+    );
+    fn run(&mut self, (owned, pos, net_id, mut connection): Self::SystemData) {
+        for (_, pos, net_id) in (&owned, &pos, &net_id).join() {
+            connection.outbound.push(Packet {
+                net_id: *net_id,
+                x: pos.vec.x,
+                y: pos.vec.y,
+            });
+        }
+        // No real transport exists yet, so stand in for one with a simulated
+        // peer: loop each outbound packet back into inbound under a distinct
+        // NetId, as if a remote peer echoed it. This also keeps outbound from
+        // growing unbounded, and exercises the path where ReceiveSys spawns a
+        // Remote entity rather than just rewriting the Owned one it came from.
+        for packet in connection.outbound.drain(..) {
+            connection.inbound.push_back(Packet {
+                net_id: NetId(packet.net_id.0 + REMOTE_ID_OFFSET),
+                x: packet.x,
+                y: packet.y,
+            });
+        }
+    }
+}
+
+struct ReceiveSys;
+impl<'a> System<'a> for ReceiveSys {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, Position>,
+        WriteStorage<'a, NetId>,
+        WriteStorage<'a, Remote>,
+        WriteStorage<'a, Geometry>,
+        WriteStorage<'a, Collider>,
+        WriteExpect<'a, Connection>,
+    );
+    fn run(
+        &mut self,
+        (entities, mut pos, mut net_id, mut remote, mut geometry, mut collider, mut connection): Self::SystemData,
+    ) {
+        let known: HashMap<u32, Entity> = (&entities, &net_id)
+            .join()
+            .map(|(entity, net_id)| (net_id.0, entity))
+            .collect();
+        while let Some(packet) = connection.inbound.pop_front() {
+            if let Some(&entity) = known.get(&packet.net_id.0) {
+                if let Some(pos) = pos.get_mut(entity) {
+                    pos.prev = pos.vec;
+                    pos.vec = Vec::new(packet.x, packet.y);
+                    pos.dirty = true;
+                }
+            } else {
+                let entity = entities.create();
+                pos.insert(entity, Position::new(Vec::new(packet.x, packet.y)))
+                    .unwrap();
+                net_id.insert(entity, packet.net_id).unwrap();
+                remote.insert(entity, Remote).unwrap();
+                geometry.insert(entity, Geometry::Circle(20.0)).unwrap();
+                collider.insert(entity, Collider::new()).unwrap();
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct BvhSys {
+    leaf_reader: Option<ReaderId<ComponentEvent>>,
+    leaves: HashMap<u32, DBVTLeafId>,
+}
+impl<'a> System<'a> for BvhSys {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, Position>,
         ReadStorage<'a, Geometry>,
+        WriteStorage<'a, BvhLeaf>,
         WriteExpect<'a, Bvh>,
     );
-    fn run(&mut self, (pos, geometry, mut bvh): Self::SystemData) {
-        *bvh = Bvh::new();
-        for (pos, geometry) in (&pos, &geometry).join() {
-            let shape: Box<HasBoundingVolume<Float, AABB<Float>>> = match geometry {
-                Geometry::Circle(radius) => Box::new(Ball::new(*radius)),
-                Geometry::Square(length) => {
-                    Box::new(Cuboid::new(Vec::new(length / 2.0, length / 2.0)))
+
+    fn setup(&mut self, res: &mut Resources) {
+        Self::SystemData::setup(res);
+        self.leaf_reader = Some(WriteStorage::<BvhLeaf>::fetch(&res).register_reader());
+    }
+
+    fn run(&mut self, (entities, mut pos, geometry, mut leaf, mut bvh): Self::SystemData) {
+        for event in leaf.channel().read(self.leaf_reader.as_mut().unwrap()) {
+            if let ComponentEvent::Removed(id) = event {
+                if let Some(stale) = self.leaves.remove(id) {
+                    bvh.0.remove(stale);
                 }
-            };
+            }
+        }
+
+        for (entity, pos, geometry) in (&entities, &mut pos, &geometry).join() {
+            let has_leaf = leaf.get(entity).is_some();
+            if !pos.dirty && has_leaf {
+                continue;
+            }
+            let shape = bounding_shape(geometry);
             let iso = Isometry::new(pos.vec, 0.0);
             let bv = aabb(shape.as_ref(), &iso);
-            let leaf = DBVTLeaf::new(bv, ());
-            bvh.0.insert(leaf);
+            if let Some(stale) = self.leaves.remove(&entity.id()) {
+                bvh.0.remove(stale);
+            }
+            let id = bvh.0.insert(DBVTLeaf::new(bv, entity));
+            self.leaves.insert(entity.id(), id);
+            leaf.insert(entity, BvhLeaf).unwrap();
+            pos.dirty = false;
+        }
+    }
+}
+
+#[derive(Default)]
+struct SpatialHashSys;
+impl<'a> System<'a> for SpatialHashSys {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Geometry>,
+        ReadExpect<'a, Broadphase>,
+        WriteExpect<'a, Candidates>,
+    );
+    fn run(&mut self, (entities, pos, geometry, broadphase, mut candidates): Self::SystemData) {
+        if *broadphase != Broadphase::SpatialHash {
+            return;
+        }
+        candidates.0.clear();
+        let mut cells = vec![];
+        for (entity, pos, geometry) in (&entities, &pos, &geometry).join() {
+            let shape = bounding_shape(geometry);
+            let iso = Isometry::new(pos.vec, 0.0);
+            let bv = aabb(shape.as_ref(), &iso);
+            let min_x = (bv.mins().x / CELL_SIZE).floor() as i32;
+            let min_y = (bv.mins().y / CELL_SIZE).floor() as i32;
+            let max_x = (bv.maxs().x / CELL_SIZE).floor() as i32;
+            let max_y = (bv.maxs().y / CELL_SIZE).floor() as i32;
+            for cy in min_y..=max_y {
+                for cx in min_x..=max_x {
+                    cells.push((morton_key(cx, cy), entity));
+                }
+            }
+        }
+        cells.sort_by_key(|&(key, _)| key);
+        let mut seen = HashSet::new();
+        let mut start = 0;
+        while start < cells.len() {
+            let mut end = start + 1;
+            while end < cells.len() && cells[end].0 == cells[start].0 {
+                end += 1;
+            }
+            for i in start..end {
+                for j in (i + 1)..end {
+                    let a = cells[i].1;
+                    let b = cells[j].1;
+                    let pair = if a.id() < b.id() { (a, b) } else { (b, a) };
+                    if seen.insert(pair) {
+                        candidates.0.push(pair);
+                    }
+                }
+            }
+            start = end;
+        }
+    }
+}
+
+const RESTITUTION: Float = 0.8;
+
+fn narrow_phase_contact(
+    pos_a: &Position,
+    geometry_a: &Geometry,
+    pos_b: &Position,
+    geometry_b: &Geometry,
+) -> Option<(Vec, Float)> {
+    let iso_a = Isometry::new(pos_a.vec, 0.0);
+    let iso_b = Isometry::new(pos_b.vec, 0.0);
+    let shape_a = narrow_shape(geometry_a);
+    let shape_b = narrow_shape(geometry_b);
+    query::contact(&iso_a, shape_a.as_ref(), &iso_b, shape_b.as_ref(), 0.0).map(|contact| {
+        // `resolve_collision` assumes `normal` points from `b` towards `a`
+        // and pushes/impulses the two bodies apart along that direction.
+        // ncollide2d's contact normal points out of the first shape towards
+        // the second (for two balls it is `center_b - center_a`, normalized),
+        // so it is negated here. Check the result against the actual centers:
+        // a flipped convention would make colliding bodies sink into each
+        // other instead of separating.
+        let normal = -*contact.normal;
+        let center_to_center = Vec::new(pos_a.vec.x - pos_b.vec.x, pos_a.vec.y - pos_b.vec.y);
+        debug_assert!(
+            normal.x * center_to_center.x + normal.y * center_to_center.y >= 0.0,
+            "contact normal points from `a` to `b`, not `b` to `a` as resolve_collision assumes"
+        );
+        (normal, contact.depth)
+    })
+}
+
+fn resolve_collision(
+    pos: &mut WriteStorage<Position>,
+    vel: &mut WriteStorage<Velocity>,
+    a: Entity,
+    b: Entity,
+    normal: Vec,
+    depth: Float,
+) {
+    let v_rel = match (vel.get(a), vel.get(b)) {
+        (Some(va), Some(vb)) => va.vec - vb.vec,
+        _ => Vec::new(0.0, 0.0),
+    };
+    let closing = v_rel.x * normal.x + v_rel.y * normal.y;
+    if closing < 0.0 {
+        let j = -(1.0 + RESTITUTION) * closing / 2.0;
+        if let Some(va) = vel.get_mut(a) {
+            va.vec.x += j * normal.x;
+            va.vec.y += j * normal.y;
+        }
+        if let Some(vb) = vel.get_mut(b) {
+            vb.vec.x -= j * normal.x;
+            vb.vec.y -= j * normal.y;
         }
     }
+    if let Some(pa) = pos.get_mut(a) {
+        pa.vec.x += normal.x * depth / 2.0;
+        pa.vec.y += normal.y * depth / 2.0;
+        pa.dirty = true;
+    }
+    if let Some(pb) = pos.get_mut(b) {
+        pb.vec.x -= normal.x * depth / 2.0;
+        pb.vec.y -= normal.y * depth / 2.0;
+        pb.dirty = true;
+    }
 }
 
 #[derive(Default)]
 struct CollideSys;
 impl<'a> System<'a> for CollideSys {
     type SystemData = (
+        Entities<'a>,
         ReadExpect<'a, Bvh>,
-        ReadStorage<'a, Position>,
+        ReadExpect<'a, Broadphase>,
+        ReadExpect<'a, Candidates>,
+        WriteStorage<'a, Position>,
+        WriteStorage<'a, Velocity>,
         ReadStorage<'a, Geometry>,
         WriteStorage<'a, Collider>,
     );
-    fn run(&mut self, (bvh, pos, geometry, mut collider): Self::SystemData) {
-        for (pos, geometry, collider) in (&pos, &geometry, &mut collider).join() {
-            let shape: Box<HasBoundingVolume<Float, AABB<Float>>> = match geometry {
-                Geometry::Circle(radius) => Box::new(Ball::new(*radius)),
-                Geometry::Square(length) => {
-                    Box::new(Cuboid::new(Vec::new(length / 2.0, length / 2.0)))
+    fn run(
+        &mut self,
+        (entities, bvh, broadphase, candidates, mut pos, mut vel, geometry, mut collider): Self::SystemData,
+    ) {
+        let mut hits = vec![];
+        let mut contacts = vec![];
+
+        match *broadphase {
+            Broadphase::Dbvt => {
+                let mut pairs = HashSet::new();
+                for (entity, self_pos, self_geometry) in (&entities, &pos, &geometry).join() {
+                    let shape = bounding_shape(self_geometry);
+                    let iso = Isometry::new(self_pos.vec, 0.0);
+                    let bv = aabb(shape.as_ref(), &iso);
+                    let mut overlaps = vec![];
+                    bvh.0
+                        .visit(&mut BoundingVolumeInterferencesCollector::new(&bv, &mut overlaps));
+                    for leaf in &overlaps {
+                        let other = leaf.data;
+                        if other == entity {
+                            continue;
+                        }
+                        let pair = if entity.id() < other.id() {
+                            (entity, other)
+                        } else {
+                            (other, entity)
+                        };
+                        pairs.insert(pair);
+                    }
                 }
-            };
-            let iso = Isometry::new(pos.vec, 0.0);
-            let bv = aabb(shape.as_ref(), &iso);
-            let mut collisions = vec![];
-            bvh.0.visit(&mut BoundingVolumeInterferencesCollector::new(&bv, &mut collisions));
-            if collisions.len() > 1 { collider.colliding = true };
+                for (a, b) in pairs {
+                    if let (Some(pos_a), Some(geometry_a), Some(pos_b), Some(geometry_b)) =
+                        (pos.get(a), geometry.get(a), pos.get(b), geometry.get(b))
+                    {
+                        if let Some((normal, depth)) =
+                            narrow_phase_contact(pos_a, geometry_a, pos_b, geometry_b)
+                        {
+                            hits.push(a);
+                            hits.push(b);
+                            contacts.push((a, b, normal, depth));
+                        }
+                    }
+                }
+            }
+            Broadphase::SpatialHash => {
+                for &(a, b) in candidates.0.iter() {
+                    if let (Some(pos_a), Some(geometry_a), Some(pos_b), Some(geometry_b)) =
+                        (pos.get(a), geometry.get(a), pos.get(b), geometry.get(b))
+                    {
+                        if let Some((normal, depth)) =
+                            narrow_phase_contact(pos_a, geometry_a, pos_b, geometry_b)
+                        {
+                            hits.push(a);
+                            hits.push(b);
+                            contacts.push((a, b, normal, depth));
+                        }
+                    }
+                }
+            }
+        }
+
+        for entity in hits {
+            if let Some(collider) = collider.get_mut(entity) {
+                collider.colliding = true;
+            }
+        }
+        for (a, b, normal, depth) in contacts {
+            resolve_collision(&mut pos, &mut vel, a, b, normal, depth);
         }
     }
 }
@@ -147,6 +629,7 @@ impl<'a> System<'a> for CleanSys {
 
 struct RenderSys<'a> {
     ctx: &'a mut Context,
+    alpha: Float,
 }
 impl<'a> System<'a> for RenderSys<'a> {
     type SystemData = (
@@ -156,6 +639,8 @@ impl<'a> System<'a> for RenderSys<'a> {
     );
     fn run(&mut self, (pos, geometry, collider): Self::SystemData) {
         for (pos, geometry, collider) in (&pos, &geometry, &collider).join() {
+            let x = pos.prev.x + (pos.vec.x - pos.prev.x) * self.alpha;
+            let y = pos.prev.y + (pos.vec.y - pos.prev.y) * self.alpha;
             let color = if collider.colliding { RED } else { WHITE };
             graphics::set_color(self.ctx, color).unwrap();
             match geometry {
@@ -163,7 +648,7 @@ impl<'a> System<'a> for RenderSys<'a> {
                     graphics::circle(
                         self.ctx,
                         graphics::DrawMode::Line(1.0),
-                        Point2::new(pos.vec.x, pos.vec.y),
+                        Point2::new(x, y),
                         *radius,
                         0.1,
                     )
@@ -173,7 +658,7 @@ impl<'a> System<'a> for RenderSys<'a> {
                     graphics::rectangle(
                         self.ctx,
                         graphics::DrawMode::Line(1.0),
-                        Rect::new(pos.vec.x - length / 2.0, pos.vec.y - length / 2.0, *length, *length),
+                        Rect::new(x - length / 2.0, y - length / 2.0, *length, *length),
                     )
                     .unwrap();
                 }
@@ -182,46 +667,258 @@ impl<'a> System<'a> for RenderSys<'a> {
     }
 }
 
+struct HudSys<'a> {
+    ctx: &'a mut Context,
+    font: &'a Font,
+    text_cache: &'a mut Option<(String, Text)>,
+}
+impl<'a> System<'a> for HudSys<'a> {
+    type SystemData = (
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Collider>,
+        ReadExpect<'a, Broadphase>,
+    );
+    fn run(&mut self, (pos, collider, broadphase): Self::SystemData) {
+        let entity_count = (&pos).join().count();
+        let colliding_count = (&collider).join().filter(|collider| collider.colliding).count();
+        let fps = timer::get_fps(self.ctx);
+        let stats = format!(
+            "fps: {:.0}  entities: {}  colliding: {}  broadphase: {:?}",
+            fps, entity_count, colliding_count, *broadphase
+        );
+        let stale = match self.text_cache {
+            Some((cached, _)) => *cached != stats,
+            None => true,
+        };
+        if stale {
+            if let Ok(text) = Text::new(self.ctx, &stats, self.font) {
+                *self.text_cache = Some((stats, text));
+            }
+        }
+        if let Some((_, text)) = self.text_cache {
+            graphics::set_color(self.ctx, WHITE).unwrap();
+            graphics::draw(self.ctx, text, Point2::new(8.0, 8.0), 0.0).unwrap();
+        }
+    }
+}
+
+struct EntitySnapshot {
+    position: Position,
+    velocity: Option<Velocity>,
+    geometry: Option<Geometry>,
+    collider: Option<Collider>,
+    emitter: Option<Emitter>,
+    lifetime: Option<Lifetime>,
+    kill_on_collision: bool,
+    net_id: Option<NetId>,
+    owned: bool,
+    remote: bool,
+}
+struct Snapshot {
+    entities: Vec<EntitySnapshot>,
+    prng: Prng,
+}
+impl Snapshot {
+    fn capture(world: &World) -> Snapshot {
+        let pos = world.read_storage::<Position>();
+        let vel = world.read_storage::<Velocity>();
+        let geometry = world.read_storage::<Geometry>();
+        let collider = world.read_storage::<Collider>();
+        let emitter = world.read_storage::<Emitter>();
+        let lifetime = world.read_storage::<Lifetime>();
+        let kill_on_collision = world.read_storage::<KillOnCollision>();
+        let net_id = world.read_storage::<NetId>();
+        let owned = world.read_storage::<Owned>();
+        let remote = world.read_storage::<Remote>();
+        let entities = world.entities();
+        let mut snapshot = vec![];
+        for (pos, entity) in (&pos, &entities).join() {
+            snapshot.push(EntitySnapshot {
+                position: Position {
+                    vec: pos.vec,
+                    prev: pos.prev,
+                    dirty: true,
+                },
+                velocity: vel.get(entity).cloned(),
+                geometry: geometry.get(entity).cloned(),
+                collider: collider.get(entity).cloned(),
+                emitter: emitter.get(entity).cloned(),
+                lifetime: lifetime.get(entity).cloned(),
+                kill_on_collision: kill_on_collision.get(entity).is_some(),
+                net_id: net_id.get(entity).cloned(),
+                owned: owned.get(entity).is_some(),
+                remote: remote.get(entity).is_some(),
+            });
+        }
+        let prng = world.read_resource::<Prng>().clone();
+        Snapshot {
+            entities: snapshot,
+            prng,
+        }
+    }
+    fn restore(&self, world: &mut World) {
+        {
+            let entities = world.entities();
+            for entity in (&entities).join() {
+                entities.delete(entity).unwrap();
+            }
+        }
+        world.maintain();
+        for frame in &self.entities {
+            let mut builder = world
+                .create_entity()
+                .with(Position {
+                    vec: frame.position.vec,
+                    prev: frame.position.prev,
+                    dirty: true,
+                });
+            if let Some(velocity) = frame.velocity.clone() {
+                builder = builder.with(velocity);
+            }
+            if let Some(geometry) = frame.geometry {
+                builder = builder.with(geometry);
+            }
+            if let Some(collider) = frame.collider.clone() {
+                builder = builder.with(collider);
+            }
+            if let Some(emitter) = frame.emitter.clone() {
+                builder = builder.with(emitter);
+            }
+            if let Some(lifetime) = frame.lifetime.clone() {
+                builder = builder.with(lifetime);
+            }
+            if frame.kill_on_collision {
+                builder = builder.with(KillOnCollision);
+            }
+            if let Some(net_id) = frame.net_id {
+                builder = builder.with(net_id);
+            }
+            if frame.owned {
+                builder = builder.with(Owned);
+            }
+            if frame.remote {
+                builder = builder.with(Remote);
+            }
+            builder.build();
+        }
+        *world.write_resource::<Prng>() = self.prng.clone();
+        world.maintain();
+    }
+}
+
 struct MainState {
     world: World,
+    dispatcher: Dispatcher<'static, 'static>,
+    accumulator: Float,
+    snapshot: Option<Snapshot>,
+    hud_font: Font,
+    hud_text_cache: Option<(String, Text)>,
 }
 impl MainState {
     fn new(_ctx: &mut Context) -> GameResult<MainState> {
+        let hud_font = Font::default_font()?;
         let mut world = World::new();
         world.register::<Position>();
         world.register::<Velocity>();
         world.register::<Geometry>();
         world.register::<Collider>();
+        world.register::<BvhLeaf>();
+        world.register::<Emitter>();
+        world.register::<Lifetime>();
+        world.register::<KillOnCollision>();
+        world.register::<NetId>();
+        world.register::<Owned>();
+        world.register::<Remote>();
         world.add_resource(Bvh::new());
+        world.add_resource(Broadphase::default());
+        world.add_resource(Candidates::default());
+        world.add_resource(Prng::new());
+        world.add_resource(Connection::new());
         world
             .create_entity()
             .with(Position::new(Vec::new(100.0, 100.0)))
             .with(Geometry::Circle(20.0))
             .with(Collider::new())
+            .with(NetId(0))
+            .with(Owned)
+            .build();
+        world
+            .create_entity()
+            .with(Position::new(Vec::new(400.0, 50.0)))
+            .with(Emitter::new(2.0, PI * 2.0, 80.0, 6.0))
             .build();
-        let state = MainState { world };
-        Ok(state)
-    }
-}
-impl event::EventHandler for MainState {
-    fn update(&mut self, _ctx: &mut Context) -> GameResult<()> {
         let mut dispatcher = DispatcherBuilder::new()
+            .with(ReceiveSys, "receive", &[])
             .with(CleanSys, "clean", &[])
             .with(VelocitySys, "velocity", &["clean"])
-            .with(BvhSys, "bvh", &["velocity"])
-            .with(CollideSys, "collide", &["bvh"])
+            .with(EmitterSys, "emitter", &["clean"])
+            .with(BvhSys::default(), "bvh", &["velocity", "emitter", "receive"])
+            .with(
+                SpatialHashSys,
+                "spatial_hash",
+                &["velocity", "emitter", "receive"],
+            )
+            .with(CollideSys, "collide", &["bvh", "spatial_hash"])
+            .with(LifetimeSys, "lifetime", &["collide"])
+            .with(TransmitSys, "transmit", &["lifetime"])
             .build();
-        dispatcher.dispatch(&mut self.world.res);
-        self.world.maintain();
+        dispatcher.setup(&mut world.res);
+        let state = MainState {
+            world,
+            dispatcher,
+            accumulator: 0.0,
+            snapshot: None,
+            hud_font,
+            hud_text_cache: None,
+        };
+        Ok(state)
+    }
+}
+impl event::EventHandler for MainState {
+    fn update(&mut self, ctx: &mut Context) -> GameResult<()> {
+        let seconds = timer::duration_to_f64(timer::get_delta(ctx)) as Float;
+        // Clamp so a long stall (breakpoint, alt-tab, GC hitch) can't queue up
+        // an unbounded number of fixed ticks and freeze the app catching up.
+        self.accumulator += seconds.min(MAX_FRAME_TIME);
+        while self.accumulator >= DT {
+            self.dispatcher.dispatch(&mut self.world.res);
+            self.world.maintain();
+            self.accumulator -= DT;
+        }
         Ok(())
     }
     fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
         graphics::clear(ctx);
-        let mut render_sys = RenderSys { ctx };
+        let alpha = self.accumulator / DT;
+        let mut render_sys = RenderSys { ctx, alpha };
         render_sys.run_now(&self.world.res);
+        let mut hud_sys = HudSys {
+            ctx,
+            font: &self.hud_font,
+            text_cache: &mut self.hud_text_cache,
+        };
+        hud_sys.run_now(&self.world.res);
         graphics::present(ctx);
         Ok(())
     }
+    fn key_down_event(&mut self, _ctx: &mut Context, keycode: Keycode, _keymod: Mod, _repeat: bool) {
+        match keycode {
+            Keycode::S => self.snapshot = Some(Snapshot::capture(&self.world)),
+            Keycode::R => {
+                if let Some(ref snapshot) = self.snapshot {
+                    snapshot.restore(&mut self.world);
+                }
+            }
+            Keycode::B => {
+                let mut broadphase = self.world.write_resource::<Broadphase>();
+                *broadphase = match *broadphase {
+                    Broadphase::Dbvt => Broadphase::SpatialHash,
+                    Broadphase::SpatialHash => Broadphase::Dbvt,
+                };
+            }
+            _ => {}
+        }
+    }
     fn mouse_button_down_event(
         &mut self,
         _ctx: &mut Context,